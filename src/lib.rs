@@ -6,11 +6,14 @@ extern crate serde_derive;
 use reqwest::{StatusCode, Url};
 use reqwest::header;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ops::Sub;
 
 const SECONDS_PER_YEAR: u64 = 31_536_000;
 const SECONDS_PER_DAY: u64 = 86_400;
+// Below this many requests left in the window, proactively mint a fresh token rather
+// than waiting for the reset window or the ttl to run out.
+const DEFAULT_RATELIMIT_RENEW_THRESHOLD: f64 = 10.0;
 
 #[derive(Debug)]
 pub enum OAuthError {
@@ -88,7 +91,7 @@ pub fn fetch_token(
     Err(OAuthError::Other(Box::new(response)))
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct OAuth {
     pub access_token: String,
     pub ttl: Duration,
@@ -105,6 +108,32 @@ impl OAuth {
     }
 }
 
+// Shared by should_renew_for_ratelimit (single-token callers) and PooledToken::is_exhausted
+// (TokenPool), so both renewal paths agree on what "low headroom" means.
+fn ratelimit_needs_renewal(remaining: f64, threshold: f64) -> bool {
+    remaining < threshold
+}
+
+// A second renewal trigger alongside OAuth::should_renew: a crawl can stall well before
+// ttl expiry if it simply runs out of rate-limit headroom first, so renew proactively
+// once state.ratelimit_remaining drops below state.ratelimit_renew_threshold.
+pub fn should_renew_for_ratelimit(_oauth: &OAuth, state: &State) -> bool {
+    ratelimit_needs_renewal(state.ratelimit_remaining, state.ratelimit_renew_threshold)
+}
+
+fn duration_as_secs_f64(dur: Duration) -> f64 {
+    dur.as_secs() as f64 + f64::from(dur.subsec_nanos()) / 1_000_000_000.0
+}
+
+fn duration_from_secs_f64(secs: f64) -> Duration {
+    if secs <= 0.0 {
+        return Duration::from_secs(0);
+    }
+    let whole_secs = secs.trunc();
+    let nanos = ((secs - whole_secs) * 1_000_000_000.0) as u32;
+    Duration::from_secs(whole_secs as u64) + Duration::from_nanos(u64::from(nanos))
+}
+
 // dur1 - dur2 fails if dur2 > dur1. this fn will just return a zero-length duration in that case.
 fn safe_duration_sub(dur1: Duration, dur2: Duration) -> Duration {
     if dur1 < dur2 {
@@ -114,6 +143,75 @@ fn safe_duration_sub(dur1: Duration, dur2: Duration) -> Duration {
     }
 }
 
+// A token bucket: `limit` tokens refill over `per`, and acquire() blocks until one is
+// available. Starts full so a fresh crawler can burst right away.
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    allowance: f32,
+    last_checked: Instant,
+    limit: f32,
+    per: Duration,
+}
+
+impl Bucket {
+    fn new(limit: f32, per: Duration) -> Bucket {
+        Bucket {
+            allowance: limit,
+            last_checked: Instant::now(),
+            limit,
+            per,
+        }
+    }
+
+    fn acquire(&mut self) {
+        let now = Instant::now();
+        let elapsed = duration_as_secs_f64(now.duration_since(self.last_checked)) as f32;
+        self.last_checked = now;
+
+        let per_secs = duration_as_secs_f64(self.per) as f32;
+        self.allowance = (self.allowance + elapsed * (self.limit / per_secs)).min(self.limit);
+
+        if self.allowance < 1.0 {
+            let wait_secs = (1.0 - self.allowance) * per_secs / self.limit;
+            std::thread::sleep(duration_from_secs_f64(f64::from(wait_secs)));
+            // The token we waited for was earned during the sleep, so refill from here.
+            self.last_checked = Instant::now();
+            self.allowance = 0.0;
+        } else {
+            self.allowance -= 1.0;
+        }
+    }
+}
+
+// Paces requests against two simultaneous ceilings, e.g. Reddit's 1-request-per-second
+// minimum spacing and its longer rolling window (100 requests / 600s). A request must
+// satisfy every bucket, so acquire() blocks on whichever is currently most constrained.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimiter {
+    per_second: Bucket,
+    per_window: Bucket,
+}
+
+impl RateLimiter {
+    pub fn new(per_second_limit: f32, per_window_limit: f32, window: Duration) -> RateLimiter {
+        RateLimiter {
+            per_second: Bucket::new(per_second_limit, Duration::from_secs(1)),
+            per_window: Bucket::new(per_window_limit, window),
+        }
+    }
+
+    pub fn acquire(&mut self) {
+        self.per_second.acquire();
+        self.per_window.acquire();
+    }
+}
+
+impl std::default::Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter::new(1.0, 100.0, Duration::from_secs(600))
+    }
+}
+
 impl std::default::Default for OAuth {
     // default oauth token will always trigger renewal.
     fn default() -> OAuth {
@@ -133,6 +231,120 @@ pub struct Creds {
     pub app_secret: String,
 }
 
+// A single app's token plus the quota bookkeeping needed to know when it's worth using.
+#[derive(Debug)]
+struct PooledToken {
+    creds: Creds,
+    oauth: OAuth,
+    // None until the token has actually served a request and Reddit has told us where
+    // it stands, so a freshly (re)fetched token isn't mistaken for an exhausted one.
+    ratelimit_remaining: Option<f64>,
+    ratelimit_reset_at: SystemTime,
+}
+
+impl PooledToken {
+    fn is_exhausted(&self, ratelimit_renew_threshold: f64) -> bool {
+        OAuth::should_renew(&self.oauth)
+            || self
+                .ratelimit_remaining
+                .map_or(false, |remaining| {
+                    ratelimit_needs_renewal(remaining, ratelimit_renew_threshold)
+                })
+    }
+}
+
+// Multiplexes several Reddit apps' tokens so aggregate throughput scales with the number
+// of creds handed in, instead of being bound to a single app's quota. Tokens are handed
+// out round-robin; an exhausted or bad token is refreshed in place and the pool moves on
+// to the next healthy one rather than stalling the whole crawl.
+#[derive(Debug)]
+pub struct TokenPool {
+    tokens: Vec<PooledToken>,
+    next: usize,
+    ratelimit_renew_threshold: f64,
+}
+
+impl TokenPool {
+    pub fn new(
+        creds: Vec<Creds>,
+        user_agent: &str,
+        client: &reqwest::Client,
+        ratelimit_renew_threshold: f64,
+    ) -> Result<TokenPool, OAuthError> {
+        let tokens = creds
+            .into_iter()
+            .map(|creds| {
+                let oauth = fetch_token(&creds, user_agent, client)?;
+                Ok(PooledToken {
+                    creds,
+                    oauth,
+                    ratelimit_remaining: None,
+                    ratelimit_reset_at: UNIX_EPOCH,
+                })
+            })
+            .collect::<Result<Vec<PooledToken>, OAuthError>>()?;
+
+        Ok(TokenPool {
+            tokens,
+            next: 0,
+            ratelimit_renew_threshold,
+        })
+    }
+
+    // Returns the index and a clone of the next healthy app's oauth token, refreshing it
+    // first if its ttl has lapsed or its ratelimit headroom is gone. Callers pass the
+    // index back to record_ratelimit/mark_bad_token after using the token.
+    pub fn next_token(
+        &mut self,
+        user_agent: &str,
+        client: &reqwest::Client,
+    ) -> Result<(usize, OAuth), OAuthError> {
+        let len = self.tokens.len();
+        let mut last_err = None;
+
+        for _ in 0..len {
+            let idx = self.next;
+            self.next = (self.next + 1) % len;
+
+            if self.tokens[idx].is_exhausted(self.ratelimit_renew_threshold) {
+                // next_token takes &mut self, so calls are already serialized and only
+                // one rollover for a given idx can ever be in flight at a time.
+                let creds = self.tokens[idx].creds.clone();
+                match fetch_token(&creds, user_agent, client) {
+                    Ok(oauth) => {
+                        self.tokens[idx].oauth = oauth;
+                        self.tokens[idx].ratelimit_remaining = None;
+                        self.tokens[idx].ratelimit_reset_at = UNIX_EPOCH;
+                    }
+                    Err(err) => {
+                        last_err = Some(err);
+                        continue;
+                    }
+                }
+            }
+
+            return Ok((idx, self.tokens[idx].oauth.clone()));
+        }
+
+        Err(last_err.unwrap_or(OAuthError::BadAppCreds))
+    }
+
+    // Records a pooled token's latest quota snapshot, usually taken straight off a
+    // cloud_search response's headers.
+    pub fn record_ratelimit(&mut self, idx: usize, remaining: f64, reset_at: SystemTime) {
+        self.tokens[idx].ratelimit_remaining = Some(remaining);
+        self.tokens[idx].ratelimit_reset_at = reset_at;
+    }
+
+    // Forces the token at idx to be refreshed the next time it's handed out, e.g. after
+    // cloud_search comes back with ApiError::BadToken.
+    pub fn mark_bad_token(&mut self, idx: usize) {
+        // A default oauth token always triggers renewal, whatever the threshold is.
+        self.tokens[idx].oauth = OAuth::default();
+        self.tokens[idx].ratelimit_remaining = Some(0.0);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct State {
     subreddit: String,
@@ -144,6 +356,13 @@ pub struct State {
     max: SystemTime,
     interval: Duration,
     prev_request_at: SystemTime,
+    // Quota bookkeeping lifted from Reddit's X-Ratelimit-* response headers.
+    ratelimit_remaining: f64,
+    ratelimit_reset_at: SystemTime,
+    ratelimit_renew_threshold: f64,
+    // The hard per-second/per-window floor crawl() enforces on every call, regardless of
+    // whether the caller goes through Crawler or drives State/crawl() directly.
+    rate_limiter: RateLimiter,
 }
 
 // Stuff the user configures to create init state struct.
@@ -153,6 +372,11 @@ pub struct Config {
     pub min_interval: Duration,
     pub max_interval: Duration,
     pub init_max: SystemTime,
+    // Requests-remaining floor that triggers should_renew_for_ratelimit.
+    pub ratelimit_renew_threshold: f64,
+    // Seeds State's RateLimiter, so callers can tune the dual-ceiling pacing floor
+    // (e.g. to match a higher/lower per-app quota) instead of being stuck with the default.
+    pub rate_limiter: RateLimiter,
 }
 
 impl std::default::Default for Config {
@@ -162,6 +386,8 @@ impl std::default::Default for Config {
             min_interval: Duration::from_secs(60 * 10),
             max_interval: Duration::from_secs(SECONDS_PER_YEAR),
             init_max: SystemTime::now(),
+            ratelimit_renew_threshold: DEFAULT_RATELIMIT_RENEW_THRESHOLD,
+            rate_limiter: RateLimiter::default(),
         }
     }
 }
@@ -178,6 +404,12 @@ impl State {
             interval: config.init_interval,
             max: config.init_max + reddit_offset,
             prev_request_at: UNIX_EPOCH,
+            // Unknown until the first response comes back, so assume a single request's
+            // worth of quota is left and pace conservatively until we learn better.
+            ratelimit_remaining: 1.0,
+            ratelimit_reset_at: UNIX_EPOCH,
+            ratelimit_renew_threshold: config.ratelimit_renew_threshold,
+            rate_limiter: config.rate_limiter,
         }
     }
 }
@@ -190,12 +422,40 @@ pub enum ApiError {
     Other(Box<reqwest::Response>),
 }
 
+// Quota snapshot lifted off Reddit's X-Ratelimit-* response headers.
+#[derive(Clone, Copy, Debug)]
+struct RatelimitInfo {
+    remaining: f64,
+    reset_at: SystemTime,
+}
+
+fn get_header_f64(headers: &header::Headers, name: &str) -> Option<f64> {
+    headers
+        .get_raw(name)
+        .and_then(|raw| raw.one())
+        .and_then(|bytes| std::str::from_utf8(bytes).ok())
+        .and_then(|s| s.trim().parse::<f64>().ok())
+}
+
+// Reddit sends X-Ratelimit-Remaining, X-Ratelimit-Used, and X-Ratelimit-Reset on every
+// oauth.reddit.com response. We only need Remaining (requests left in the window) and
+// Reset (seconds until the window rolls over) to pace ourselves.
+fn get_ratelimit_info(headers: &header::Headers) -> Option<RatelimitInfo> {
+    let remaining = get_header_f64(headers, "X-Ratelimit-Remaining")?;
+    let reset_secs = get_header_f64(headers, "X-Ratelimit-Reset")?;
+
+    Some(RatelimitInfo {
+        remaining,
+        reset_at: SystemTime::now() + Duration::from_secs(reset_secs as u64),
+    })
+}
+
 fn cloud_search(
     oauth: &OAuth,
     state: &State,
     user_agent: &str,
     client: &reqwest::Client,
-) -> Result<(Vec<Submission>, Option<String>), ApiError> {
+) -> Result<(Vec<Submission>, Option<String>, RatelimitInfo), ApiError> {
     let q = {
         // Clamp lower bound to epoch to handle distance_between(epoch, max) > interval
         let min = if state.max <= UNIX_EPOCH {
@@ -251,12 +511,18 @@ fn cloud_search(
     }
 
     if res.status() == StatusCode::Ok {
+        // Read the headers before consuming the body with .json().
+        let ratelimit = get_ratelimit_info(res.headers()).unwrap_or(RatelimitInfo {
+            remaining: state.ratelimit_remaining,
+            reset_at: state.ratelimit_reset_at,
+        });
+
         match res.json::<CloudSearchResponse>() {
             Ok(body) => {
                 let subs = body.data.children.into_iter().map(|x| x.data).collect();
                 let after = body.data.after;
 
-                return Ok((subs, after));
+                return Ok((subs, after, ratelimit));
             }
             Err(err) => return Err(ApiError::UnexpectedBody(err)),
         }
@@ -314,21 +580,39 @@ pub fn crawl(
     user_agent: &str,
     client: &reqwest::Client,
 ) -> Result<Option<(Vec<Submission>, State)>, ApiError> {
-    // Ensure a second has elapsed since last request.
+    // Dual-ceiling floor: blocks on whichever of the per-second/per-window buckets is
+    // currently most constrained. This is the one pacing guarantee every caller of crawl()
+    // gets, whether or not they're going through Crawler/TokenPool.
+    let mut rate_limiter = state.rate_limiter;
+    rate_limiter.acquire();
+
+    // Spread the remaining quota evenly across the remaining reset window instead of
+    // sleeping a fixed second, so we go as fast as the quota allows without tripping a 429.
+    // This is on top of, not instead of, the RateLimiter floor above: the adaptive figure
+    // is only as good as Reddit's last response, which may be stale or missing.
     {
-        let one_second = Duration::from_secs(1);
-        let elapsed = SystemTime::now()
-            .duration_since(state.prev_request_at)
-            .unwrap();
-        let delay = if elapsed >= one_second {
-            Duration::from_secs(0)
+        let now = SystemTime::now();
+
+        let delay = if state.ratelimit_remaining < 1.0 {
+            state
+                .ratelimit_reset_at
+                .duration_since(now)
+                .unwrap_or_else(|_| Duration::from_secs(0))
         } else {
-            one_second - elapsed
+            let reset_secs = duration_as_secs_f64(
+                state
+                    .ratelimit_reset_at
+                    .duration_since(now)
+                    .unwrap_or_else(|_| Duration::from_secs(0)),
+            );
+            let computed = duration_from_secs_f64(reset_secs / state.ratelimit_remaining.max(1.0));
+            std::cmp::max(Duration::from_secs(1), computed)
         };
+
         std::thread::sleep(delay);
     }
 
-    let (subs, next_after) = cloud_search(oauth, state, user_agent, client)?;
+    let (subs, next_after, ratelimit) = cloud_search(oauth, state, user_agent, client)?;
 
     // If we queried with maxInterval and still found nothing,
     // then we assume we've reached the end of the subreddit
@@ -402,12 +686,171 @@ pub fn crawl(
         page: next_page,
         interval: next_interval,
         prev_request_at: SystemTime::now(),
+        ratelimit_remaining: ratelimit.remaining,
+        ratelimit_reset_at: ratelimit.reset_at,
+        rate_limiter,
         ..state.clone()
     };
 
     Ok(Some((subs, next_state)))
 }
 
+// One pending crawl(), tracked so RequestQueue knows how many times it's already
+// been retried.
+#[derive(Clone, Debug)]
+struct QueuedRequest {
+    state: State,
+    attempt: u32,
+}
+
+// Whether a failure is transient and worth retrying, vs. one that should bubble
+// straight up to the caller.
+fn is_retryable(err: &ApiError) -> bool {
+    match *err {
+        ApiError::NetworkError(_) => true,
+        ApiError::Other(ref res) => res.status().is_server_error(),
+        ApiError::BadToken | ApiError::UnexpectedBody(_) => false,
+    }
+}
+
+// The single choke point between Crawler and reqwest: retries transient
+// ApiError::NetworkError/5xx responses with capped exponential backoff instead of
+// letting them bubble up and abort a crawl mid-subreddit. Every page, fresh or
+// retried, is drained through crawl() here, so every attempt gets the same pacing
+// and retry policy.
+struct RequestQueue {
+    pending: VecDeque<QueuedRequest>,
+    base_delay: Duration,
+    max_attempts: u32,
+    max_delay: Duration,
+}
+
+impl RequestQueue {
+    fn new(initial: State) -> RequestQueue {
+        let mut pending = VecDeque::new();
+        pending.push_back(QueuedRequest {
+            state: initial,
+            attempt: 0,
+        });
+
+        RequestQueue {
+            pending,
+            base_delay: Duration::from_secs(1),
+            max_attempts: 5,
+            max_delay: Duration::from_secs(60),
+        }
+    }
+
+    // Drains the queue, retrying transient failures in place, until it yields a page of
+    // submissions, confirms the crawl is done, or a non-retryable error escapes. `pool`
+    // and `idx` identify which pooled token this drain is spending, so the queue can feed
+    // the token's quota snapshot back and flag it bad on ApiError::BadToken.
+    fn next_page(
+        &mut self,
+        pool: &mut TokenPool,
+        idx: usize,
+        oauth: &OAuth,
+        user_agent: &str,
+        client: &reqwest::Client,
+    ) -> Result<Option<Vec<Submission>>, ApiError> {
+        while let Some(job) = self.pending.pop_front() {
+            match crawl(oauth, &job.state, user_agent, client) {
+                Ok(None) => return Ok(None),
+                Ok(Some((subs, next_state))) => {
+                    pool.record_ratelimit(
+                        idx,
+                        next_state.ratelimit_remaining,
+                        next_state.ratelimit_reset_at,
+                    );
+                    self.pending.push_back(QueuedRequest {
+                        state: next_state,
+                        attempt: 0,
+                    });
+                    return Ok(Some(subs));
+                }
+                Err(ApiError::BadToken) => {
+                    // The token, not this page, is at fault — keep the job queued so
+                    // the caller's retry (with a fresh/rotated token) picks it straight
+                    // back up instead of silently dropping this page of the crawl.
+                    self.pending.push_front(job);
+                    return Err(ApiError::BadToken);
+                }
+                Err(err) => {
+                    if !is_retryable(&err) || job.attempt + 1 >= self.max_attempts {
+                        return Err(err);
+                    }
+
+                    let backoff = (self.base_delay * 2u32.pow(job.attempt)).min(self.max_delay);
+                    std::thread::sleep(backoff);
+
+                    self.pending.push_back(QueuedRequest {
+                        state: job.state,
+                        attempt: job.attempt + 1,
+                    });
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+// Owns the token pool, the queue, and the reqwest client, hiding all the
+// requeueing/backoff/pacing/token-rotation behind a single next_page() call.
+pub struct Crawler {
+    pool: TokenPool,
+    user_agent: String,
+    client: reqwest::Client,
+    queue: RequestQueue,
+}
+
+impl Crawler {
+    pub fn new(
+        subreddit: String,
+        config: &Config,
+        creds: Vec<Creds>,
+        user_agent: String,
+        client: reqwest::Client,
+    ) -> Result<Crawler, OAuthError> {
+        let pool = TokenPool::new(
+            creds,
+            &user_agent,
+            &client,
+            config.ratelimit_renew_threshold,
+        )?;
+        let state = State::new(subreddit, config);
+
+        Ok(Crawler {
+            pool,
+            user_agent,
+            client,
+            queue: RequestQueue::new(state),
+        })
+    }
+
+    // Returns the next page of submissions, or None once the subreddit is exhausted.
+    // Transient failures are retried internally; a bad token is flagged on the pool and
+    // the page re-attempted against the next healthy (or freshly-refreshed) one.
+    pub fn next_page(&mut self) -> Result<Option<Vec<Submission>>, ApiError> {
+        loop {
+            let (idx, oauth) = self
+                .pool
+                .next_token(&self.user_agent, &self.client)
+                .map_err(|_| ApiError::BadToken)?;
+
+            match self
+                .queue
+                .next_page(&mut self.pool, idx, &oauth, &self.user_agent, &self.client)
+            {
+                Err(ApiError::BadToken) => {
+                    self.pool.mark_bad_token(idx);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
 // Ensure a value exists in an inclusive range. If out of range, the violated bound is returned.
 //
 //     clamp(-1, 3, 10) == 3